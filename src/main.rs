@@ -1,3 +1,4 @@
+use clap::Parser;
 use nom::{
     bytes::complete::{tag, take_until},
     combinator::opt,
@@ -5,15 +6,320 @@ use nom::{
     sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
-use rocket::{State, http::Status, routes, serde::json::Json};
+use rocket::{
+    Orbit, Rocket, State,
+    fairing::{Fairing, Info, Kind},
+    http::Status,
+    routes,
+    serde::json::Json,
+};
 use serde::Serialize;
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{FromRow, SqlitePool, sqlite::SqliteConnectOptions};
 use std::{
-    io::{BufRead, BufReader},
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader},
     mem,
+    str::FromStr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
     time::Duration,
 };
 
+use rocket::tokio::sync::watch;
+
+/// How long to wait before retrying a failed serial device open, or before
+/// reopening a device that dropped out mid-stream.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// DSMR meter generation: picks serial defaults and the set of OBIS fields
+/// worth looking for, so the same binary works across meter generations.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum DsmrProfile {
+    /// DSMR 2.2 / 3.0 — 9600 baud, 7E1, no CRC, electricity-only fields.
+    #[default]
+    V2,
+    /// DSMR 4 / 5 — 115200 baud, 8N1, CRC16, gas/voltage/current/event fields.
+    V4,
+}
+
+impl DsmrProfile {
+    fn default_baud_rate(self) -> u32 {
+        match self {
+            DsmrProfile::V2 => 9600,
+            DsmrProfile::V4 => 115_200,
+        }
+    }
+
+    fn default_data_bits(self) -> serialport::DataBits {
+        match self {
+            DsmrProfile::V2 => serialport::DataBits::Seven,
+            DsmrProfile::V4 => serialport::DataBits::Eight,
+        }
+    }
+
+    fn default_parity(self) -> serialport::Parity {
+        match self {
+            DsmrProfile::V2 => serialport::Parity::Even,
+            DsmrProfile::V4 => serialport::Parity::None,
+        }
+    }
+
+    /// Whether this profile's telegrams carry the gas/voltage/current/event
+    /// fields introduced in DSMR 4.
+    fn has_extended_fields(self) -> bool {
+        matches!(self, DsmrProfile::V4)
+    }
+}
+
+/// Converts a validated data-bits value. `n` is guaranteed to be in `5..=8`
+/// by the `value_parser` on `Config::data_bits`.
+fn data_bits_from_u8(n: u8) -> serialport::DataBits {
+    match n {
+        5 => serialport::DataBits::Five,
+        6 => serialport::DataBits::Six,
+        7 => serialport::DataBits::Seven,
+        _ => serialport::DataBits::Eight,
+    }
+}
+
+/// Serial parity, mirrored here so it can derive `clap::ValueEnum`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ParityArg {
+    None,
+    Odd,
+    Even,
+}
+
+impl From<ParityArg> for serialport::Parity {
+    fn from(parity: ParityArg) -> Self {
+        match parity {
+            ParityArg::None => serialport::Parity::None,
+            ParityArg::Odd => serialport::Parity::Odd,
+            ParityArg::Even => serialport::Parity::Even,
+        }
+    }
+}
+
+/// CLI/env configuration. Reads from `.env` (via `dotenvy`) and the process
+/// environment in addition to flags, so the binary can be configured the
+/// same way in development and behind a systemd unit.
+#[derive(clap::Parser, Debug)]
+#[command(version, about = "DSMR P1 telegram ingest and HTTP API")]
+struct Config {
+    /// Path to the P1 serial device.
+    #[arg(long, env = "SERIAL_DEVICE", default_value = "/dev/ttyUSB0")]
+    serial_device: String,
+
+    /// Baud rate. Defaults to the right value for `--profile` if unset.
+    #[arg(long, env = "BAUD_RATE")]
+    baud_rate: Option<u32>,
+
+    /// Serial data bits (5-8). Defaults to the right value for `--profile` if unset.
+    #[arg(long, env = "DATA_BITS", value_parser = clap::value_parser!(u8).range(5..=8))]
+    data_bits: Option<u8>,
+
+    /// Serial parity. Defaults to the right value for `--profile` if unset.
+    #[arg(long, env = "SERIAL_PARITY", value_enum)]
+    parity: Option<ParityArg>,
+
+    /// DSMR meter generation; selects serial defaults and the parsed field set.
+    #[arg(long, env = "DSMR_PROFILE", value_enum, default_value = "v2")]
+    profile: DsmrProfile,
+
+    /// SQLite connection URL.
+    #[arg(long, env = "DATABASE_URL", default_value = "sqlite://./dev.db")]
+    database_url: String,
+}
+
+/// Resolved serial port settings, derived from `Config` and the chosen
+/// `DsmrProfile`'s defaults.
+struct SerialSettings {
+    device: String,
+    baud_rate: u32,
+    data_bits: serialport::DataBits,
+    parity: serialport::Parity,
+    profile: DsmrProfile,
+}
+
+impl Config {
+    fn serial_settings(&self) -> SerialSettings {
+        SerialSettings {
+            device: self.serial_device.clone(),
+            baud_rate: self.baud_rate.unwrap_or_else(|| self.profile.default_baud_rate()),
+            data_bits: self.data_bits.map(data_bits_from_u8).unwrap_or_else(|| self.profile.default_data_bits()),
+            parity: self.parity.map(Into::into).unwrap_or_else(|| self.profile.default_parity()),
+            profile: self.profile,
+        }
+    }
+}
+
+/// Gauge values and timing of the most recently stored frame.
+#[derive(Default)]
+struct LastFrame {
+    timestamp: i64,
+    actual_delivered: Option<f32>,
+    actual_received: Option<f32>,
+    current_tariff: Option<i32>,
+    gas: Option<f32>,
+    voltage_l1: Option<f32>,
+    voltage_l2: Option<f32>,
+    voltage_l3: Option<f32>,
+    current_l1: Option<f32>,
+    current_l2: Option<f32>,
+    current_l3: Option<f32>,
+    power_failures: Option<i32>,
+    long_power_failures: Option<i32>,
+}
+
+/// Shared ingest counters, updated from the serial reader thread and
+/// rendered by the `/metrics` endpoint.
+///
+/// Kept behind an `Arc` so it can be handed to Rocket's state alongside
+/// the DB pool.
+#[derive(Default)]
+struct Stats {
+    frames_received: AtomicU64,
+    frames_crc_ok: AtomicU64,
+    frames_crc_failed: AtomicU64,
+    inserts_failed: AtomicU64,
+    field_parse_failures: Mutex<HashMap<&'static str, u64>>,
+    last_frame: Mutex<Option<LastFrame>>,
+}
+
+impl Stats {
+    fn bump_field_failure(&self, id: &'static str) {
+        *self.field_parse_failures.lock().unwrap().entry(id).or_insert(0) += 1;
+    }
+
+    fn record_frame(&self, timestamp: i64, frame: &DataFrame) {
+        *self.last_frame.lock().unwrap() = Some(LastFrame {
+            timestamp,
+            actual_delivered: frame.actual_delivered,
+            actual_received: frame.actual_received,
+            current_tariff: frame.current_tariff,
+            gas: frame.gas,
+            voltage_l1: frame.voltage_l1,
+            voltage_l2: frame.voltage_l2,
+            voltage_l3: frame.voltage_l3,
+            current_l1: frame.current_l1,
+            current_l2: frame.current_l2,
+            current_l3: frame.current_l3,
+            power_failures: frame.power_failures,
+            long_power_failures: frame.long_power_failures,
+        });
+    }
+
+    /// Render all counters/gauges in Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# HELP kaminari_frames_received_total Total number of P1 telegrams received.").unwrap();
+        writeln!(out, "# TYPE kaminari_frames_received_total counter").unwrap();
+        writeln!(out, "kaminari_frames_received_total {}", self.frames_received.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP kaminari_frames_crc_ok_total Telegrams accepted with a valid (or absent) CRC16.").unwrap();
+        writeln!(out, "# TYPE kaminari_frames_crc_ok_total counter").unwrap();
+        writeln!(out, "kaminari_frames_crc_ok_total {}", self.frames_crc_ok.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP kaminari_frames_crc_failed_total Telegrams dropped due to a CRC16 mismatch.").unwrap();
+        writeln!(out, "# TYPE kaminari_frames_crc_failed_total counter").unwrap();
+        writeln!(out, "kaminari_frames_crc_failed_total {}", self.frames_crc_failed.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP kaminari_inserts_failed_total Frames that failed to insert into the database.").unwrap();
+        writeln!(out, "# TYPE kaminari_inserts_failed_total counter").unwrap();
+        writeln!(out, "kaminari_inserts_failed_total {}", self.inserts_failed.load(Ordering::Relaxed)).unwrap();
+
+        writeln!(out, "# HELP kaminari_field_parse_failures_total Per-OBIS-field parse failures.").unwrap();
+        writeln!(out, "# TYPE kaminari_field_parse_failures_total counter").unwrap();
+        for (id, count) in self.field_parse_failures.lock().unwrap().iter() {
+            writeln!(out, "kaminari_field_parse_failures_total{{obis=\"{}\"}} {}", id, count).unwrap();
+        }
+
+        if let Some(last_frame) = self.last_frame.lock().unwrap().as_ref() {
+            writeln!(out, "# HELP kaminari_last_frame_timestamp_seconds Unix timestamp of the last successfully stored frame.").unwrap();
+            writeln!(out, "# TYPE kaminari_last_frame_timestamp_seconds gauge").unwrap();
+            writeln!(out, "kaminari_last_frame_timestamp_seconds {}", last_frame.timestamp).unwrap();
+
+            if let Some(actual_delivered) = last_frame.actual_delivered {
+                writeln!(out, "# HELP kaminari_actual_delivered_watts Most recently observed actual power delivered.").unwrap();
+                writeln!(out, "# TYPE kaminari_actual_delivered_watts gauge").unwrap();
+                writeln!(out, "kaminari_actual_delivered_watts {}", actual_delivered * 1000.0).unwrap();
+            }
+
+            if let Some(actual_received) = last_frame.actual_received {
+                writeln!(out, "# HELP kaminari_actual_received_watts Most recently observed actual power received.").unwrap();
+                writeln!(out, "# TYPE kaminari_actual_received_watts gauge").unwrap();
+                writeln!(out, "kaminari_actual_received_watts {}", actual_received * 1000.0).unwrap();
+            }
+
+            if let Some(current_tariff) = last_frame.current_tariff {
+                writeln!(out, "# HELP kaminari_current_tariff Currently active tariff indicator.").unwrap();
+                writeln!(out, "# TYPE kaminari_current_tariff gauge").unwrap();
+                writeln!(out, "kaminari_current_tariff {}", current_tariff).unwrap();
+            }
+
+            if let Some(gas) = last_frame.gas {
+                writeln!(out, "# HELP kaminari_gas_cubic_meters Most recently observed cumulative gas reading.").unwrap();
+                writeln!(out, "# TYPE kaminari_gas_cubic_meters gauge").unwrap();
+                writeln!(out, "kaminari_gas_cubic_meters {}", gas).unwrap();
+            }
+
+            let voltages = [('1', last_frame.voltage_l1), ('2', last_frame.voltage_l2), ('3', last_frame.voltage_l3)];
+            if voltages.iter().any(|(_, v)| v.is_some()) {
+                writeln!(out, "# HELP kaminari_voltage_volts Most recently observed instantaneous voltage per phase.").unwrap();
+                writeln!(out, "# TYPE kaminari_voltage_volts gauge").unwrap();
+                for (phase, voltage) in voltages {
+                    if let Some(voltage) = voltage {
+                        writeln!(out, "kaminari_voltage_volts{{phase=\"{}\"}} {}", phase, voltage).unwrap();
+                    }
+                }
+            }
+
+            let currents = [('1', last_frame.current_l1), ('2', last_frame.current_l2), ('3', last_frame.current_l3)];
+            if currents.iter().any(|(_, c)| c.is_some()) {
+                writeln!(out, "# HELP kaminari_current_amperes Most recently observed instantaneous current per phase.").unwrap();
+                writeln!(out, "# TYPE kaminari_current_amperes gauge").unwrap();
+                for (phase, current) in currents {
+                    if let Some(current) = current {
+                        writeln!(out, "kaminari_current_amperes{{phase=\"{}\"}} {}", phase, current).unwrap();
+                    }
+                }
+            }
+
+            if let Some(power_failures) = last_frame.power_failures {
+                writeln!(out, "# HELP kaminari_power_failures Number of power failures reported by the meter.").unwrap();
+                writeln!(out, "# TYPE kaminari_power_failures gauge").unwrap();
+                writeln!(out, "kaminari_power_failures {}", power_failures).unwrap();
+            }
+
+            if let Some(long_power_failures) = last_frame.long_power_failures {
+                writeln!(out, "# HELP kaminari_long_power_failures Number of entries in the long power failure event log.").unwrap();
+                writeln!(out, "# TYPE kaminari_long_power_failures gauge").unwrap();
+                writeln!(out, "kaminari_long_power_failures {}", long_power_failures).unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+/// CRC16/ARC (poly 0xA001, init 0x0000), as used by DSMR P1 telegrams.
+fn crc16_arc(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0x0000;
+    for &byte in bytes {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            }
+            else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
 #[derive(Debug, Default)]
 struct DataFrame {
     delivered_1: Option<f32>,
@@ -25,6 +331,19 @@ struct DataFrame {
     actual_received: Option<f32>,
     max_power: Option<f32>,
     switch_mode: Option<i32>,
+    /// Raw DSMR capture timestamp (e.g. `201129200000W`) of the gas reading.
+    gas_timestamp: Option<String>,
+    gas: Option<f32>,
+    voltage_l1: Option<f32>,
+    voltage_l2: Option<f32>,
+    voltage_l3: Option<f32>,
+    current_l1: Option<f32>,
+    current_l2: Option<f32>,
+    current_l3: Option<f32>,
+    power_failures: Option<i32>,
+    /// Count of entries in the long power failure event log (`1-0:99.97.0`);
+    /// the individual timestamped events are not captured.
+    long_power_failures: Option<i32>,
 }
 
 fn parse_float(id: &'static str) -> impl Fn(&str) -> IResult<&str, f32> {
@@ -46,74 +365,290 @@ fn parse_float(id: &'static str) -> impl Fn(&str) -> IResult<&str, f32> {
     }
 }
 
-fn try_field_f32(line: &str, id: &'static str, field: &mut Option<f32>) {
-    if let Ok((_, res)) = parse_float(id)(line) {
-        field.replace(res);
+fn try_field_f32(line: &str, id: &'static str, field: &mut Option<f32>, stats: &Stats) {
+    if !line.starts_with(id) {
+        return;
+    }
+
+    match parse_float(id)(line) {
+        Ok((_, res)) => { field.replace(res); }
+        Err(_) => stats.bump_field_failure(id),
+    }
+}
+
+fn try_field_i32(line: &str, id: &'static str, field: &mut Option<i32>, stats: &Stats) {
+    if !line.starts_with(id) {
+        return;
+    }
+
+    match parse_float(id)(line) {
+        Ok((_, res)) => { field.replace(res as i32); }
+        Err(_) => stats.bump_field_failure(id),
     }
 }
 
-fn try_field_i32(line: &str, id: &'static str, field: &mut Option<i32>) {
-    if let Ok((_, res)) = parse_float(id)(line) {
-        field.replace(res as i32);
+/// Parses an M-Bus reading of the form `id(timestamp)(value*unit)`, as used
+/// by the gas channel (`0-1:24.2.1`): a capture timestamp followed by the
+/// reading itself in a second parenthesized group.
+fn parse_mbus_reading(id: &'static str) -> impl Fn(&str) -> IResult<&str, (&str, f32)> {
+    move |s| {
+        preceded(
+            tag(id),
+            tuple((
+                delimited(tag("("), take_until(")"), tag(")")),
+                delimited(
+                    tag("("),
+                    terminated(
+                        float,
+                        opt(tuple((
+                            tag("*"),
+                            take_until(")"),
+                        ))),
+                    ),
+                    tag(")"),
+                ),
+            )),
+        )(s)
     }
 }
 
-fn read_p1(db_pool: SqlitePool) {
-    let serial = serialport::new("/dev/ttyUSB0", 9600)
-        .timeout(Duration::from_secs(15))
-        .data_bits(serialport::DataBits::Seven)
-        .parity(serialport::Parity::Even)
-        .open()
-        .expect("opening serial device");
+fn try_field_mbus(line: &str, id: &'static str, timestamp: &mut Option<String>, value: &mut Option<f32>, stats: &Stats) {
+    if !line.starts_with(id) {
+        return;
+    }
 
-    let p1 = BufReader::new(serial);
+    match parse_mbus_reading(id)(line) {
+        Ok((_, (ts, val))) => {
+            timestamp.replace(ts.to_owned());
+            value.replace(val);
+        }
+        Err(_) => stats.bump_field_failure(id),
+    }
+}
 
-    let mut frame = DataFrame::default();
-    let frames = p1
-        .lines()
-        .filter_map(Result::ok)
-        .filter_map(|line| {
-            if line == "!" {
-                return Some(mem::take(&mut frame));
+/// Incremental DSMR telegram parser: folds lines from the serial stream
+/// into `DataFrame`s, validating CRC16 along the way.
+#[derive(Default)]
+struct TelegramReader {
+    frame: DataFrame,
+    telegram: Vec<u8>,
+    profile: DsmrProfile,
+}
+
+impl TelegramReader {
+    fn new(profile: DsmrProfile) -> Self {
+        Self { profile, ..Self::default() }
+    }
+
+    /// Feed one line (without its line ending) into the parser. Returns the
+    /// completed frame when the line closes a telegram and its CRC16 matches;
+    /// a telegram that carries no CRC is returned unchecked.
+    fn feed(&mut self, line: &str, stats: &Stats) -> Option<DataFrame> {
+        if line.starts_with('/') {
+            self.telegram.clear();
+        }
+
+        if let Some(crc_hex) = line.strip_prefix('!') {
+            self.telegram.push(b'!');
+            let frame = mem::take(&mut self.frame);
+            stats.frames_received.fetch_add(1, Ordering::Relaxed);
+
+            // DSMR 2.2/3.0 telegrams carry no CRC at all; accept them as-is.
+            if crc_hex.is_empty() {
+                stats.frames_crc_ok.fetch_add(1, Ordering::Relaxed);
+                return Some(frame);
+            }
+
+            let expected = u16::from_str_radix(crc_hex.trim(), 16).ok();
+            let actual = crc16_arc(&self.telegram);
+
+            return if expected == Some(actual) {
+                stats.frames_crc_ok.fetch_add(1, Ordering::Relaxed);
+                Some(frame)
             }
             else {
-                try_field_f32(&line, "1-0:1.8.1", &mut frame.delivered_1);
-                try_field_f32(&line, "1-0:1.8.2", &mut frame.delivered_2);
-                try_field_f32(&line, "1-0:2.8.1", &mut frame.received_1);
-                try_field_f32(&line, "1-0:2.8.2", &mut frame.received_2);
-                try_field_i32(&line, "0-0:96.14.0", &mut frame.current_tariff);
-                try_field_f32(&line, "1-0:1.7.0", &mut frame.actual_delivered);
-                try_field_f32(&line, "1-0:2.7.0", &mut frame.actual_received);
-                try_field_f32(&line, "0-0:17.0.0", &mut frame.max_power);
-                try_field_i32(&line, "0-0:96.3.10", &mut frame.switch_mode);
-            }
-
-            None
-        });
+                stats.frames_crc_failed.fetch_add(1, Ordering::Relaxed);
+                None
+            };
+        }
+        else {
+            self.telegram.extend_from_slice(line.as_bytes());
+            self.telegram.extend_from_slice(b"\r\n");
 
-    for frame in frames {
-        let db_pool = db_pool.clone();
-        rocket::tokio::task::spawn(async move {
-            let now = chrono::Utc::now().timestamp();
-
-            sqlx::query!(
-                "INSERT INTO records VALUES (NULL, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-                now,
-                frame.delivered_1,
-                frame.delivered_2,
-                frame.received_1,
-                frame.received_2,
-                frame.current_tariff,
-                frame.actual_delivered,
-                frame.actual_received,
-                frame.max_power,
-                frame.switch_mode,
-            )
-                .execute(&db_pool)
-                .await
-                .expect("insert into db");
+            try_field_f32(line, "1-0:1.8.1", &mut self.frame.delivered_1, stats);
+            try_field_f32(line, "1-0:1.8.2", &mut self.frame.delivered_2, stats);
+            try_field_f32(line, "1-0:2.8.1", &mut self.frame.received_1, stats);
+            try_field_f32(line, "1-0:2.8.2", &mut self.frame.received_2, stats);
+            try_field_i32(line, "0-0:96.14.0", &mut self.frame.current_tariff, stats);
+            try_field_f32(line, "1-0:1.7.0", &mut self.frame.actual_delivered, stats);
+            try_field_f32(line, "1-0:2.7.0", &mut self.frame.actual_received, stats);
+            try_field_f32(line, "0-0:17.0.0", &mut self.frame.max_power, stats);
+            try_field_i32(line, "0-0:96.3.10", &mut self.frame.switch_mode, stats);
+
+            if self.profile.has_extended_fields() {
+                try_field_mbus(line, "0-1:24.2.1", &mut self.frame.gas_timestamp, &mut self.frame.gas, stats);
+
+                try_field_f32(line, "1-0:32.7.0", &mut self.frame.voltage_l1, stats);
+                try_field_f32(line, "1-0:52.7.0", &mut self.frame.voltage_l2, stats);
+                try_field_f32(line, "1-0:72.7.0", &mut self.frame.voltage_l3, stats);
+                try_field_f32(line, "1-0:31.7.0", &mut self.frame.current_l1, stats);
+                try_field_f32(line, "1-0:51.7.0", &mut self.frame.current_l2, stats);
+                try_field_f32(line, "1-0:71.7.0", &mut self.frame.current_l3, stats);
 
-            println!("{:#?}", frame);
+                try_field_i32(line, "0-0:96.7.21", &mut self.frame.power_failures, stats);
+                try_field_i32(line, "1-0:99.97.0", &mut self.frame.long_power_failures, stats);
+            }
+        }
+
+        None
+    }
+}
+
+fn store_frame(db_pool: &SqlitePool, stats: &Arc<Stats>, frame: DataFrame) {
+    let db_pool = db_pool.clone();
+    let stats = stats.clone();
+    rocket::tokio::task::spawn(async move {
+        let now = chrono::Utc::now().timestamp();
+
+        let res = sqlx::query!(
+            "INSERT INTO records VALUES (NULL, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            now,
+            frame.delivered_1,
+            frame.delivered_2,
+            frame.received_1,
+            frame.received_2,
+            frame.current_tariff,
+            frame.actual_delivered,
+            frame.actual_received,
+            frame.max_power,
+            frame.switch_mode,
+            frame.gas_timestamp,
+            frame.gas,
+            frame.voltage_l1,
+            frame.voltage_l2,
+            frame.voltage_l3,
+            frame.current_l1,
+            frame.current_l2,
+            frame.current_l3,
+            frame.power_failures,
+            frame.long_power_failures,
+        )
+            .execute(&db_pool)
+            .await;
+
+        match res {
+            Ok(_) => {
+                stats.record_frame(now, &frame);
+                println!("{:#?}", frame);
+            }
+            Err(e) => {
+                stats.inserts_failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("failed to insert frame into db: {e}");
+            }
+        }
+    });
+}
+
+/// Wait out a backoff period, bailing out early if shutdown is requested.
+/// Returns `true` if shutdown fired during the wait.
+fn backoff(duration: Duration, shutdown: &watch::Receiver<bool>) -> bool {
+    let step = Duration::from_millis(200);
+    let mut waited = Duration::ZERO;
+
+    while waited < duration {
+        if *shutdown.borrow() {
+            return true;
+        }
+
+        std::thread::sleep(step);
+        waited += step;
+    }
+
+    *shutdown.borrow()
+}
+
+/// Read telegrams from an already-open serial device until it errors out
+/// or a shutdown is requested. Returns `true` if shutdown fired.
+fn read_serial(
+    serial: Box<dyn serialport::SerialPort>,
+    db_pool: &SqlitePool,
+    stats: &Arc<Stats>,
+    shutdown: &mut watch::Receiver<bool>,
+    profile: DsmrProfile,
+) -> bool {
+    let mut reader = BufReader::new(serial);
+    let mut parser = TelegramReader::new(profile);
+    let mut line = String::new();
+
+    loop {
+        if *shutdown.borrow() {
+            return true;
+        }
+
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                eprintln!("serial device closed, reconnecting");
+                return false;
+            }
+            Ok(_) => {
+                let trimmed = line.trim_end_matches(['\r', '\n']);
+                if let Some(frame) = parser.feed(trimmed, stats) {
+                    store_frame(db_pool, stats, frame);
+                }
+            }
+            // The serial port is configured with a read timeout so we can
+            // keep polling for a shutdown request while idle.
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                eprintln!("serial read error, reconnecting: {e}");
+                return false;
+            }
+        }
+    }
+}
+
+fn read_p1(db_pool: SqlitePool, stats: Arc<Stats>, mut shutdown: watch::Receiver<bool>, serial_settings: SerialSettings) {
+    while !*shutdown.borrow() {
+        let serial = serialport::new(&serial_settings.device, serial_settings.baud_rate)
+            .timeout(Duration::from_secs(15))
+            .data_bits(serial_settings.data_bits)
+            .parity(serial_settings.parity)
+            .open();
+
+        let serial = match serial {
+            Ok(serial) => serial,
+            Err(e) => {
+                eprintln!("failed to open serial device, retrying: {e}");
+                backoff(RECONNECT_BACKOFF, &shutdown);
+                continue;
+            }
+        };
+
+        if read_serial(serial, &db_pool, &stats, &mut shutdown, serial_settings.profile) {
+            return;
+        }
+
+        backoff(RECONNECT_BACKOFF, &shutdown);
+    }
+}
+
+/// Propagates Rocket's shutdown signal to the blocking serial reader
+/// thread, which cannot otherwise observe it.
+struct ShutdownPropagator(watch::Sender<bool>);
+
+#[rocket::async_trait]
+impl Fairing for ShutdownPropagator {
+    fn info(&self) -> Info {
+        Info { name: "Shutdown propagator", kind: Kind::Liftoff }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let shutdown = rocket.shutdown();
+        let tx = self.0.clone();
+        rocket::tokio::spawn(async move {
+            shutdown.await;
+            let _ = tx.send(true);
         });
     }
 }
@@ -135,6 +670,11 @@ impl Record {
     }
 }
 
+#[rocket::get("/metrics")]
+fn metrics(stats: &State<Arc<Stats>>) -> String {
+    stats.render()
+}
+
 #[rocket::get("/loadavg")]
 async fn loadavg(db_pool: &State<SqlitePool>) -> Result<String, (Status, String)> {
     let now = chrono::Utc::now().timestamp();
@@ -171,22 +711,203 @@ struct ValuesResponse {
     delivered_1: Option<f32>,
     delivered_2: Option<f32>,
     current_tariff: Option<i64>,
+    gas_timestamp: Option<String>,
+    gas: Option<f32>,
+    voltage_l1: Option<f32>,
+    voltage_l2: Option<f32>,
+    voltage_l3: Option<f32>,
+    current_l1: Option<f32>,
+    current_l2: Option<f32>,
+    current_l3: Option<f32>,
+    power_failures: Option<i64>,
+    long_power_failures: Option<i64>,
+}
+
+/// A raw row, as needed to compute a `BucketedValuesResponse`.
+#[derive(FromRow)]
+struct BucketRow {
+    timestamp: i64,
+    delivered_1: Option<f32>,
+    delivered_2: Option<f32>,
+    received_1: Option<f32>,
+    received_2: Option<f32>,
+    actual_delivered: Option<f32>,
+    actual_received: Option<f32>,
+    gas: Option<f32>,
+    voltage_l1: Option<f32>,
+    voltage_l2: Option<f32>,
+    voltage_l3: Option<f32>,
+    current_l1: Option<f32>,
+    current_l2: Option<f32>,
+    current_l3: Option<f32>,
+    power_failures: Option<i64>,
+    long_power_failures: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct BucketedValuesResponse {
+    bucket_start: i64,
+    delivered_1_first: Option<f32>,
+    delivered_1_last: Option<f32>,
+    delivered_1_min: Option<f32>,
+    delivered_1_max: Option<f32>,
+    delivered_2_first: Option<f32>,
+    delivered_2_last: Option<f32>,
+    delivered_2_min: Option<f32>,
+    delivered_2_max: Option<f32>,
+    received_1_first: Option<f32>,
+    received_1_last: Option<f32>,
+    received_1_min: Option<f32>,
+    received_1_max: Option<f32>,
+    received_2_first: Option<f32>,
+    received_2_last: Option<f32>,
+    received_2_min: Option<f32>,
+    received_2_max: Option<f32>,
+    actual_delivered_avg: Option<f32>,
+    actual_received_avg: Option<f32>,
+    /// Power delivered over the bucket, computed from the first/last
+    /// cumulative `delivered_2` readings, same math as `Record::watts_since`.
+    watts: Option<f32>,
+    gas_first: Option<f32>,
+    gas_last: Option<f32>,
+    voltage_l1_avg: Option<f32>,
+    voltage_l2_avg: Option<f32>,
+    voltage_l3_avg: Option<f32>,
+    current_l1_avg: Option<f32>,
+    current_l2_avg: Option<f32>,
+    current_l3_avg: Option<f32>,
+    power_failures_last: Option<i64>,
+    long_power_failures_last: Option<i64>,
+}
+
+fn avg(values: impl Iterator<Item = Option<f32>>) -> Option<f32> {
+    let (sum, count) = values.flatten().fold((0.0, 0u32), |(sum, count), v| (sum + v, count + 1));
+    (count > 0).then(|| sum / count as f32)
 }
 
-#[rocket::get("/values?<from>&<to>")]
+/// `(min, max)` of the non-`None` values in `values`, or `None` if all are `None`.
+fn min_max(values: impl Iterator<Item = Option<f32>>) -> (Option<f32>, Option<f32>) {
+    values.flatten().fold((None, None), |(min, max), v| {
+        (
+            Some(min.map_or(v, |min: f32| min.min(v))),
+            Some(max.map_or(v, |max: f32| max.max(v))),
+        )
+    })
+}
+
+/// Group rows into fixed-width `bucket`-second buckets and reduce each one
+/// down to first/last/min/max cumulative counters, average gauges, and power.
+fn bucket_values(mut rows: Vec<BucketRow>, bucket: i64) -> Vec<BucketedValuesResponse> {
+    rows.sort_by_key(|r| r.timestamp);
+
+    let mut buckets: Vec<(i64, Vec<BucketRow>)> = Vec::new();
+    for row in rows {
+        let bucket_start = row.timestamp / bucket * bucket;
+        match buckets.last_mut() {
+            Some((start, rows)) if *start == bucket_start => rows.push(row),
+            _ => buckets.push((bucket_start, vec![row])),
+        }
+    }
+
+    buckets
+        .into_iter()
+        .map(|(bucket_start, rows)| {
+            let first = rows.first().unwrap();
+            let last = rows.last().unwrap();
+
+            let watts = match (first.delivered_2, last.delivered_2) {
+                (Some(start), Some(end)) if last.timestamp > first.timestamp =>
+                    Some((end - start) * 3600.0 / (last.timestamp - first.timestamp) as f32 * 1000.0),
+                _ => None,
+            };
+
+            let (delivered_1_min, delivered_1_max) = min_max(rows.iter().map(|r| r.delivered_1));
+            let (delivered_2_min, delivered_2_max) = min_max(rows.iter().map(|r| r.delivered_2));
+            let (received_1_min, received_1_max) = min_max(rows.iter().map(|r| r.received_1));
+            let (received_2_min, received_2_max) = min_max(rows.iter().map(|r| r.received_2));
+
+            BucketedValuesResponse {
+                bucket_start,
+                delivered_1_first: first.delivered_1,
+                delivered_1_last: last.delivered_1,
+                delivered_1_min,
+                delivered_1_max,
+                delivered_2_first: first.delivered_2,
+                delivered_2_last: last.delivered_2,
+                delivered_2_min,
+                delivered_2_max,
+                received_1_first: first.received_1,
+                received_1_last: last.received_1,
+                received_1_min,
+                received_1_max,
+                received_2_first: first.received_2,
+                received_2_last: last.received_2,
+                received_2_min,
+                received_2_max,
+                actual_delivered_avg: avg(rows.iter().map(|r| r.actual_delivered)),
+                actual_received_avg: avg(rows.iter().map(|r| r.actual_received)),
+                watts,
+                gas_first: first.gas,
+                gas_last: last.gas,
+                voltage_l1_avg: avg(rows.iter().map(|r| r.voltage_l1)),
+                voltage_l2_avg: avg(rows.iter().map(|r| r.voltage_l2)),
+                voltage_l3_avg: avg(rows.iter().map(|r| r.voltage_l3)),
+                current_l1_avg: avg(rows.iter().map(|r| r.current_l1)),
+                current_l2_avg: avg(rows.iter().map(|r| r.current_l2)),
+                current_l3_avg: avg(rows.iter().map(|r| r.current_l3)),
+                power_failures_last: last.power_failures,
+                long_power_failures_last: last.long_power_failures,
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ValuesResult {
+    Raw(Vec<ValuesResponse>),
+    Bucketed(Vec<BucketedValuesResponse>),
+}
+
+#[rocket::get("/values?<from>&<to>&<bucket>")]
 async fn values(
     from: Option<i64>,
     to: Option<i64>,
+    bucket: Option<i64>,
     db_pool: &State<SqlitePool>,
-) -> Result<Json<Vec<ValuesResponse>>, (Status, String)> {
+) -> Result<Json<ValuesResult>, (Status, String)> {
     let (from, to) = if let (Some(from), Some(to)) = (from, to) { (from, to) }
     else {
         return Err((Status::BadRequest, "`from` and `to` query parameters are required".to_owned()));
     };
 
+    if let Some(bucket) = bucket {
+        if bucket <= 0 {
+            return Err((Status::BadRequest, "`bucket` must be a positive number of seconds".to_owned()));
+        }
+
+        let res = sqlx::query_as!(
+                BucketRow,
+                "SELECT timestamp, delivered_1, delivered_2, received_1, received_2, actual_delivered, actual_received,
+                        gas, voltage_l1, voltage_l2, voltage_l3, current_l1, current_l2, current_l3,
+                        power_failures, long_power_failures
+                 FROM records WHERE timestamp BETWEEN ? AND ?",
+                from,
+                to,
+            )
+            .fetch_all(db_pool.inner())
+            .await
+            .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+
+        return Ok(Json(ValuesResult::Bucketed(bucket_values(res, bucket))));
+    }
+
     let res = sqlx::query_as!(
             ValuesResponse,
-            "SELECT timestamp, delivered_1, delivered_2, current_tariff FROM records WHERE timestamp BETWEEN ? AND ?",
+            "SELECT timestamp, delivered_1, delivered_2, current_tariff,
+                    gas_timestamp, gas, voltage_l1, voltage_l2, voltage_l3, current_l1, current_l2, current_l3,
+                    power_failures, long_power_failures
+             FROM records WHERE timestamp BETWEEN ? AND ?",
             from,
             to,
         )
@@ -194,19 +915,171 @@ async fn values(
         .await
         .map_err(|e| (Status::InternalServerError, e.to_string()))?;
 
-    Ok(Json(res))
+    Ok(Json(ValuesResult::Raw(res)))
 }
 
 #[rocket::launch]
 async fn rocket() -> _ {
-    let db_pool = SqlitePool::connect_lazy("sqlite://./dev.db").unwrap();
+    dotenvy::dotenv().ok();
+    let config = Config::parse();
+    let serial_settings = config.serial_settings();
+
+    let db_options = SqliteConnectOptions::from_str(&config.database_url)
+        .unwrap()
+        .create_if_missing(true);
+
+    let db_pool = SqlitePool::connect_with(db_options)
+        .await
+        .expect("connecting to db");
+
+    sqlx::migrate!("./migrations")
+        .run(&db_pool)
+        .await
+        .expect("running migrations");
+
+    let stats = Arc::new(Stats::default());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
     rocket::tokio::task::spawn_blocking({
         let db_pool = db_pool.clone();
-        move || read_p1(db_pool)
+        let stats = stats.clone();
+        move || read_p1(db_pool, stats, shutdown_rx, serial_settings)
     });
 
     rocket::build()
         .manage(db_pool)
-        .mount("/", routes![ values, loadavg ])
+        .manage(stats)
+        .attach(ShutdownPropagator(shutdown_tx))
+        .mount("/", routes![ values, loadavg, metrics ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_arc_matches_known_value() {
+        // CRC16/ARC of b"123456789", the standard check value for this algorithm.
+        assert_eq!(crc16_arc(b"123456789"), 0xBB3D);
+    }
+
+    /// A DSMR v4 telegram (CRLF line endings, as sent on the wire) and its
+    /// real CRC16/ARC trailer, fed line by line the way `read_p1` does.
+    const SAMPLE_TELEGRAM: &str = "\
+/ISk5\\2MT382-1000\r\n\
+1-3:0.2.8(40)\r\n\
+0-0:1.0.0(101209113020W)\r\n\
+1-0:1.8.1(123456.789*kWh)\r\n\
+1-0:1.8.2(123456.789*kWh)\r\n\
+1-0:2.8.1(000000.000*kWh)\r\n\
+1-0:2.8.2(000000.000*kWh)\r\n\
+0-0:96.14.0(0002)\r\n\
+1-0:1.7.0(01.193*kW)\r\n\
+1-0:2.7.0(00.000*kW)\r\n\
+0-0:17.0.0(999.9*kW)\r\n\
+0-0:96.3.10(1)\r\n\
+0-1:24.2.1(101209110000W)(12785.123*m3)\r\n\
+!90B9\r\n";
+
+    #[test]
+    fn telegram_reader_accepts_telegram_with_correct_crc() {
+        let stats = Stats::default();
+        let mut reader = TelegramReader::new(DsmrProfile::V4);
+
+        let mut frame = None;
+        for line in SAMPLE_TELEGRAM.lines() {
+            frame = reader.feed(line, &stats).or(frame);
+        }
+
+        let frame = frame.expect("telegram with matching CRC should yield a frame");
+        assert_eq!(frame.delivered_1, Some(123456.789));
+        assert_eq!(frame.gas, Some(12785.123));
+        assert_eq!(stats.frames_crc_ok.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.frames_crc_failed.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn telegram_reader_rejects_telegram_with_bad_crc() {
+        let stats = Stats::default();
+        let mut reader = TelegramReader::new(DsmrProfile::V4);
+
+        let corrupted = SAMPLE_TELEGRAM.replace("!90B9", "!0000");
+        let mut frame = None;
+        for line in corrupted.lines() {
+            frame = reader.feed(line, &stats).or(frame);
+        }
+
+        assert!(frame.is_none());
+        assert_eq!(stats.frames_crc_ok.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.frames_crc_failed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn parse_mbus_reading_extracts_timestamp_and_value() {
+        let (rest, (timestamp, value)) =
+            parse_mbus_reading("0-1:24.2.1")("0-1:24.2.1(101209110000W)(12785.123*m3)").unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(timestamp, "101209110000W");
+        assert_eq!(value, 12785.123);
+    }
+
+    fn bucket_row(timestamp: i64, delivered_1: f32) -> BucketRow {
+        BucketRow {
+            timestamp,
+            delivered_1: Some(delivered_1),
+            delivered_2: None,
+            received_1: None,
+            received_2: None,
+            actual_delivered: None,
+            actual_received: None,
+            gas: None,
+            voltage_l1: None,
+            voltage_l2: None,
+            voltage_l3: None,
+            current_l1: None,
+            current_l2: None,
+            current_l3: None,
+            power_failures: None,
+            long_power_failures: None,
+        }
+    }
+
+    #[test]
+    fn bucket_values_splits_rows_crossing_a_bucket_boundary() {
+        let rows = vec![
+            bucket_row(0, 1.0),
+            bucket_row(30, 2.0),
+            bucket_row(60, 3.0),
+            bucket_row(90, 4.0),
+        ];
+
+        let buckets = bucket_values(rows, 60);
+
+        assert_eq!(buckets.len(), 2);
+
+        assert_eq!(buckets[0].bucket_start, 0);
+        assert_eq!(buckets[0].delivered_1_first, Some(1.0));
+        assert_eq!(buckets[0].delivered_1_last, Some(2.0));
+        assert_eq!(buckets[0].delivered_1_min, Some(1.0));
+        assert_eq!(buckets[0].delivered_1_max, Some(2.0));
+
+        assert_eq!(buckets[1].bucket_start, 60);
+        assert_eq!(buckets[1].delivered_1_first, Some(3.0));
+        assert_eq!(buckets[1].delivered_1_last, Some(4.0));
+        assert_eq!(buckets[1].delivered_1_min, Some(3.0));
+        assert_eq!(buckets[1].delivered_1_max, Some(4.0));
+    }
+
+    #[test]
+    fn min_max_ignores_none_values() {
+        assert_eq!(min_max([Some(3.0), None, Some(1.0), Some(2.0)].into_iter()), (Some(1.0), Some(3.0)));
+        assert_eq!(min_max([None, None].into_iter()), (None, None));
+    }
+
+    #[test]
+    fn avg_ignores_none_values() {
+        assert_eq!(avg([Some(1.0), None, Some(3.0)].into_iter()), Some(2.0));
+        assert_eq!(avg([None, None].into_iter()), None);
+    }
 }